@@ -0,0 +1,49 @@
+/// Construction du client HTTP partagé entre le chemin de transfert du proxy et les
+/// vérifications de santé périodiques
+///
+/// Un client unique et réutilisé permet de conserver un pool de connexions persistantes vers
+/// les serveurs amont plutôt que d'ouvrir une nouvelle connexion à chaque requête
+
+use hyper::client::HttpConnector;
+use hyper::Client;
+use std::time::Duration;
+
+/// Paramètres de construction du client HTTP partagé
+#[derive(Clone, Copy, Debug)]
+pub struct HttpClientConfig {
+    /// Durée maximale pendant laquelle une connexion inactive est conservée dans le pool
+    pub pool_idle_timeout: Duration,
+    /// Nombre maximal de connexions inactives conservées par hôte
+    pub pool_max_idle_per_host: usize,
+    /// Délai maximal accordé à l'établissement de la connexion TCP
+    pub connect_timeout: Duration,
+    /// Délai maximal accordé à une requête vers un serveur amont, de la connexion jusqu'à la
+    /// réception des en-têtes de la réponse ; le relais du corps de la réponse n'est pas borné
+    /// par ce délai
+    pub request_timeout: Duration,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_idle_timeout: Duration::from_secs(90),
+            pool_max_idle_per_host: 32,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Construit le client HTTP partagé à partir de la configuration fournie
+///
+/// # Arguments
+/// * "config" - Paramètres de pool de connexions et de délai de connexion
+pub fn build_http_client(config: &HttpClientConfig) -> Client<HttpConnector> {
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(Some(config.connect_timeout));
+
+    Client::builder()
+        .pool_idle_timeout(config.pool_idle_timeout)
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .build(connector)
+}
@@ -4,14 +4,54 @@
 /// entre plusieurs serveurs en fonction de leur disponibilité, tout en appliquant une politique
 /// de limitation de débit pour prévenir la surcharge.
 
+use hyper::client::HttpConnector;
+use hyper::server::conn::AddrStream;
 use hyper::{Client, Server, service::{make_service_fn, service_fn}, Body, Request};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
-use crate::upstream::UpstreamServer;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use crate::upstream::{HealthCheckConfig, UpstreamServer};
 use crate::rate_limiter::RateLimiter;
+use crate::middleware::Middleware;
+use crate::http_client::{build_http_client, HttpClientConfig};
 use log::info;
-use crate::proxy_handler::proxy_request; // Importe la fonction de gestion des requêtes proxy
+use crate::proxy_handler::{proxy_request, ProxyConfig}; // Importe la fonction de gestion des requêtes proxy
+
+/// Stratégies de répartition de charge disponibles entre les serveurs sains
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadBalancingStrategy {
+    /// Distribue les requêtes de façon cyclique entre les serveurs sains
+    RoundRobin,
+    /// Sélectionne un serveur sain de façon aléatoire et uniforme
+    Random,
+    /// Distribue les requêtes proportionnellement au poids de chaque serveur
+    WeightedRoundRobin,
+    /// Sélectionne le serveur sain ayant le moins de connexions actives
+    LeastConnections,
+}
+
+impl LoadBalancingStrategy {
+    /// Analyse une stratégie à partir de sa représentation textuelle (valeur du flag CLI)
+    ///
+    /// # Arguments
+    /// * "value" - Nom de la stratégie ("round-robin", "random", "weighted-round-robin" ou "least-connections")
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "round-robin" | "round_robin" => Some(Self::RoundRobin),
+            "random" => Some(Self::Random),
+            "weighted-round-robin" | "weighted_round_robin" => Some(Self::WeightedRoundRobin),
+            "least-connections" | "least_connections" => Some(Self::LeastConnections),
+            _ => None,
+        }
+    }
+}
+
+impl Default for LoadBalancingStrategy {
+    fn default() -> Self {
+        Self::RoundRobin
+    }
+}
 
 /// Structure principale du Load Balancer
 /// Gère la répartition des requêtes HTTP entrantes entre plusieurs serveurs
@@ -20,34 +60,87 @@ pub struct LoadBalancer {
     /// Liste des serveurs disponibles pour la répartition des requêtes
     /// Utilise "Arc" et "RwLock" pour un accès concurrent sécurisé en lecture et écriture
     pub servers: Arc<RwLock<Vec<UpstreamServer>>>,
-    
+
     /// Gestionnaire de la limitation du débit pour les requêtes
-    /// Encapsulé dans un "Arc" et un "Mutex" pour assurer la synchronisation entre les threads
-    rate_limiter: Arc<Mutex<RateLimiter>>,
+    /// Encapsulé dans un "Arc" pour un partage sécurisé entre les threads ; le "RateLimiter"
+    /// gère lui-même sa propre synchronisation selon son backend (mémoire ou Redis)
+    rate_limiter: Arc<RateLimiter>,
+
+    /// Stratégie de répartition de charge utilisée pour sélectionner le serveur suivant
+    strategy: LoadBalancingStrategy,
+
+    /// Chaîne de middlewares exécutée autour de la transmission de chaque requête vers le
+    /// serveur amont, dans l'ordre pour "on_request" et dans l'ordre inverse pour "on_response"
+    middlewares: Arc<Vec<Arc<dyn Middleware>>>,
+
+    /// Client HTTP partagé entre le chemin de transfert du proxy et les vérifications de santé,
+    /// pour réutiliser le pool de connexions plutôt que de le reconstruire à chaque requête
+    client: Client<HttpConnector>,
+
+    /// Délai maximal accordé à une requête vers un serveur amont avant de la considérer en échec
+    request_timeout: Duration,
+
+    /// Configuration des vérifications de santé périodiques appliquée à chaque serveur ajouté
+    health_check_config: HealthCheckConfig,
+
+    /// Paramètres configurables du traitement des requêtes par le proxy (tailles, tentatives, délais)
+    proxy_config: ProxyConfig,
 }
 
 impl LoadBalancer {
-    /// Constructeur pour initialiser un nouveau Load Balancer avec des paramètres spécifiques
-    /// de limitation de débit
+    /// Constructeur pour initialiser un nouveau Load Balancer avec un gestionnaire de limitation
+    /// de débit, une stratégie de répartition de charge, une chaîne de middlewares et une
+    /// configuration de client HTTP déjà préparés
     ///
     /// # Arguments
-    /// * "window_secs" - Durée de la fenêtre de limitation en secondes
-    /// * "max_requests" - Nombre maximal de requêtes autorisées par fenêtre de temps
-    pub fn new(window_secs: u64, max_requests: u32) -> Self {
+    /// * "rate_limiter" - Gestionnaire de limitation de débit, en mémoire ou adossé à Redis
+    /// * "strategy" - Stratégie de répartition de charge à utiliser pour choisir le serveur suivant
+    /// * "middlewares" - Chaîne de middlewares à exécuter autour de la transmission des requêtes
+    /// * "http_client_config" - Paramètres de pool de connexions et de délais du client HTTP partagé
+    /// * "health_check_config" - Configuration des vérifications de santé périodiques appliquée
+    ///   à chaque serveur ajouté via "add_server"
+    /// * "proxy_config" - Paramètres configurables du traitement des requêtes par le proxy
+    pub fn new(
+        rate_limiter: RateLimiter,
+        strategy: LoadBalancingStrategy,
+        middlewares: Vec<Arc<dyn Middleware>>,
+        http_client_config: HttpClientConfig,
+        health_check_config: HealthCheckConfig,
+        proxy_config: ProxyConfig,
+    ) -> Self {
         Self {
             servers: Arc::new(RwLock::new(Vec::new())), // Initialise une liste vide pour les serveurs
-            rate_limiter: Arc::new(Mutex::new(RateLimiter::new(window_secs, max_requests))),
+            rate_limiter: Arc::new(rate_limiter),
+            strategy,
+            middlewares: Arc::new(middlewares),
+            client: build_http_client(&http_client_config),
+            request_timeout: http_client_config.request_timeout,
+            health_check_config,
+            proxy_config,
         }
     }
 
-    /// Ajoute un serveur à la liste des serveurs gérés par le load balancer
+    /// Ajoute un serveur à la liste des serveurs gérés par le load balancer, avec la
+    /// configuration de vérification de santé du load balancer
     /// Cette opération est asynchrone pour éviter de bloquer l'exécution pendant la modification
     ///
     /// # Arguments
     /// * "url" - URL du serveur à ajouter
-    pub async fn add_server(&self, url: String) {
+    /// * "weight" - Poids relatif du serveur, utilisé par la stratégie "WeightedRoundRobin"
+    pub async fn add_server(&self, url: String, weight: u32) {
         let mut servers = self.servers.write().await; // Obtient un verrou en écriture
-        servers.push(UpstreamServer::new(url)); // Ajoute le nouveau serveur
+        servers.push(UpstreamServer::new(url, weight, self.health_check_config.clone())); // Ajoute le nouveau serveur
+    }
+
+    /// Renvoie un clone du client HTTP partagé, pour que la boucle de vérification de santé
+    /// réutilise le même pool de connexions que le chemin de transfert du proxy
+    pub fn http_client(&self) -> Client<HttpConnector> {
+        self.client.clone()
+    }
+
+    /// Renvoie le délai maximal configuré pour les requêtes vers les serveurs amont
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
     }
 
     /// Démarre l'exécution asynchrone du serveur de load balancing
@@ -59,18 +152,24 @@ impl LoadBalancer {
     pub async fn run(&self, addr: SocketAddr) {
         let rate_limiter = self.rate_limiter.clone();
         let servers = self.servers.clone();
-        let client = Client::new(); // Client HTTP pour envoyer des requêtes aux serveurs
+        let client = self.client.clone();
+        let strategy = self.strategy;
+        let middlewares = self.middlewares.clone();
+        let request_timeout = self.request_timeout;
+        let proxy_config = self.proxy_config;
 
         // Préparation de la logique de service pour traiter les requêtes entrantes
-        let make_svc = make_service_fn(move |_| {
+        let make_svc = make_service_fn(move |conn: &AddrStream| {
+            let remote_addr = conn.remote_addr();
             let rate_limiter = rate_limiter.clone();
             let servers = servers.clone();
             let client = client.clone();
+            let middlewares = middlewares.clone();
 
             // Utilise "proxy_request" pour répondre aux requêtes
             async move {
                 Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
-                    proxy_request(req, rate_limiter.clone(), servers.clone(), client.clone())
+                    proxy_request(req, rate_limiter.clone(), servers.clone(), client.clone(), strategy, middlewares.clone(), remote_addr, request_timeout, proxy_config)
                 }))
             }
         });
@@ -6,6 +6,7 @@
 /// des serveurs sont également effectuées
 
 use env_logger::Env;
+use log::info;
 use std::net::SocketAddr;
 use clap::{App, Arg};
 
@@ -13,8 +14,17 @@ mod load_balancer;
 mod upstream;
 mod rate_limiter;
 mod proxy_handler;
+mod middleware;
+mod http_client;
 
-use crate::load_balancer::LoadBalancer;
+use crate::http_client::HttpClientConfig;
+use crate::load_balancer::{LoadBalancer, LoadBalancingStrategy};
+use crate::middleware::{ForwardedHeadersMiddleware, HopByHopHeadersMiddleware, Middleware};
+use crate::proxy_handler::ProxyConfig;
+use crate::rate_limiter::RateLimiter;
+use crate::upstream::HealthCheckConfig;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Point d'entrée du programme
 ///
@@ -37,23 +47,137 @@ async fn main() {
              .long("port")
              .takes_value(true)
              .help("Définit le port d'écoute pour le load balancer."))
+        .arg(Arg::new("strategy")
+             .long("strategy")
+             .takes_value(true)
+             .help("Stratégie de répartition de charge : round-robin, random, weighted-round-robin ou least-connections."))
+        .arg(Arg::new("redis-url")
+             .long("redis-url")
+             .takes_value(true)
+             .help("URL de connexion Redis (ex: redis://127.0.0.1/) pour une limitation de débit partagée entre instances. Si absent, la limitation de débit reste locale au processus."))
+        .arg(Arg::new("health-check-interval")
+             .long("health-check-interval")
+             .takes_value(true)
+             .help("Intervalle en secondes entre deux sondes de santé consécutives (défaut : 20)."))
+        .arg(Arg::new("health-check-timeout")
+             .long("health-check-timeout")
+             .takes_value(true)
+             .help("Délai en secondes accordé à une sonde de santé avant de la considérer en échec (défaut : 5)."))
+        .arg(Arg::new("health-check-path")
+             .long("health-check-path")
+             .takes_value(true)
+             .help("Chemin dédié interrogé par les sondes de santé (défaut : /health)."))
+        .arg(Arg::new("health-check-healthy-threshold")
+             .long("health-check-healthy-threshold")
+             .takes_value(true)
+             .help("Nombre de sondes consécutives réussies avant qu'un serveur non sain ne rejoigne la rotation (défaut : 2)."))
+        .arg(Arg::new("health-check-unhealthy-threshold")
+             .long("health-check-unhealthy-threshold")
+             .takes_value(true)
+             .help("Nombre de sondes consécutives échouées avant qu'un serveur sain ne soit éjecté (défaut : 3)."))
+        .arg(Arg::new("max-buffered-body-size")
+             .long("max-buffered-body-size")
+             .takes_value(true)
+             .help("Taille maximale en octets du corps de requête mis en mémoire tampon pour le rejouer entre serveurs candidats (défaut : 10485760)."))
+        .arg(Arg::new("max-retry-attempts")
+             .long("max-retry-attempts")
+             .takes_value(true)
+             .help("Nombre maximal de serveurs candidats essayés avant d'abandonner la requête (défaut : 3)."))
+        .arg(Arg::new("request-body-read-timeout")
+             .long("request-body-read-timeout")
+             .takes_value(true)
+             .help("Délai en secondes accordé à la lecture complète du corps d'une requête entrante (défaut : 10)."))
         .get_matches();
 
     // Détermine le port d'écoute à partir des arguments CLI, avec une valeur par défaut si non spécifié
     let port = matches.value_of("port").unwrap_or("8080");
     let addr = format!("127.0.0.1:{}", port).parse::<SocketAddr>().unwrap();
 
-    // Crée une instance du LoadBalancer avec des paramètres de limitation de débit spécifiques
-    let load_balancer = LoadBalancer::new(60, 100); // Utilise une fenêtre de 60 secondes et un max de 100 requêtes
+    // Détermine la stratégie de répartition de charge à partir des arguments CLI
+    let strategy_arg = matches.value_of("strategy").unwrap_or("round-robin");
+    let strategy = LoadBalancingStrategy::parse(strategy_arg).unwrap_or_else(|| {
+        eprintln!("Stratégie inconnue : {}, utilisation de round-robin par défaut.", strategy_arg);
+        LoadBalancingStrategy::RoundRobin
+    });
+
+    // Construit le gestionnaire de limitation de débit : adossé à Redis si une URL est fournie,
+    // sinon en mémoire dans le processus. Utilise une fenêtre de 60 secondes et un max de 100 requêtes
+    let rate_limiter = match matches.value_of("redis-url") {
+        Some(redis_url) => {
+            let manager = bb8_redis::RedisConnectionManager::new(redis_url)
+                .expect("URL Redis invalide");
+            let pool = bb8_redis::bb8::Pool::builder()
+                .build(manager)
+                .await
+                .expect("Impossible d'établir le pool de connexions Redis");
+            info!("Limitation de débit adossée à Redis : {}", redis_url);
+            RateLimiter::new_redis(pool, 60, 100)
+        }
+        None => RateLimiter::new_in_memory(60, 100),
+    };
+
+    // Chaîne de middlewares appliquée à chaque requête : injection des en-têtes "Forwarded"
+    // suivie du retrait des en-têtes "hop-by-hop"
+    let middlewares: Vec<Arc<dyn Middleware>> = vec![
+        Arc::new(ForwardedHeadersMiddleware),
+        Arc::new(HopByHopHeadersMiddleware),
+    ];
+
+    // Construit la configuration des vérifications de santé périodiques à partir des arguments CLI,
+    // en repliant sur les valeurs par défaut en cas d'absence ou d'échec d'analyse
+    let default_health_check = HealthCheckConfig::default();
+    let health_check_config = HealthCheckConfig {
+        interval: matches.value_of("health-check-interval")
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(default_health_check.interval),
+        request_timeout: matches.value_of("health-check-timeout")
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(default_health_check.request_timeout),
+        probe_path: matches.value_of("health-check-path")
+            .map(String::from)
+            .unwrap_or(default_health_check.probe_path),
+        healthy_threshold: matches.value_of("health-check-healthy-threshold")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default_health_check.healthy_threshold),
+        unhealthy_threshold: matches.value_of("health-check-unhealthy-threshold")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default_health_check.unhealthy_threshold),
+    };
+
+    // Construit les paramètres configurables du traitement des requêtes par le proxy à partir des
+    // arguments CLI, en repliant sur les valeurs par défaut en cas d'absence ou d'échec d'analyse
+    let default_proxy_config = ProxyConfig::default();
+    let proxy_config = ProxyConfig {
+        max_buffered_body_size: matches.value_of("max-buffered-body-size")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default_proxy_config.max_buffered_body_size),
+        max_retry_attempts: matches.value_of("max-retry-attempts")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default_proxy_config.max_retry_attempts),
+        request_body_read_timeout: matches.value_of("request-body-read-timeout")
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(default_proxy_config.request_body_read_timeout),
+    };
+
+    // Crée une instance du LoadBalancer avec le gestionnaire de limitation de débit, la stratégie,
+    // la chaîne de middlewares, le client HTTP partagé, la configuration de santé et les
+    // paramètres du proxy choisis
+    let load_balancer = LoadBalancer::new(rate_limiter, strategy, middlewares, HttpClientConfig::default(), health_check_config, proxy_config);
 
     // Ajoute des serveurs au load balancer de manière asynchrone.
-    load_balancer.add_server("http://127.0.0.1:3000".to_string()).await;
-    load_balancer.add_server("http://127.0.0.1:3001".to_string()).await;
+    load_balancer.add_server("http://127.0.0.1:3000".to_string(), 1).await;
+    load_balancer.add_server("http://127.0.0.1:3001".to_string(), 1).await;
 
-    // Lance une routine asynchrone pour effectuer des vérifications périodiques de l'état de santé des serveurs
+    // Lance une routine asynchrone pour effectuer des vérifications périodiques de l'état de santé
+    // des serveurs, en réutilisant le même client HTTP partagé que le chemin de transfert du proxy ;
+    // chaque serveur est sondé à son propre intervalle, configuré lors de son ajout
     let servers = load_balancer.servers.clone();
+    let health_client = load_balancer.http_client();
     tokio::spawn(async move {
-        upstream::health_check_loop(servers).await;
+        upstream::health_check_loop(servers, health_client).await;
     });
 
     // Démarre le load balancer pour écouter sur l'adresse spécifiée et traiter les requêtes entrantes
@@ -0,0 +1,96 @@
+/// Chaîne de middlewares pour inspecter et transformer les requêtes et réponses du proxy
+///
+/// Ce module définit le trait "Middleware", exécuté par "proxy_request" autour de la
+/// transmission vers le serveur amont : les "on_request" s'exécutent dans l'ordre de
+/// déclaration avant l'envoi, les "on_response" dans l'ordre inverse après réception de la
+/// réponse. Il fournit également les middlewares intégrés pour l'injection des en-têtes
+/// "Forwarded" et le retrait des en-têtes "hop-by-hop"
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, Response};
+use std::net::SocketAddr;
+
+/// Adresse du client distant, déposée dans les extensions de la requête sortante afin que les
+/// middlewares puissent y accéder sans dépendre d'un en-tête déjà présent
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// En-têtes "hop-by-hop" au sens de la RFC 7230 section 6.1 : spécifiques à une connexion et qui
+/// ne doivent jamais être transmis tels quels à un serveur en aval
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailers",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Un maillon de la chaîne de middlewares du proxy
+///
+/// Implémenté pour des traitements comme l'ajout d'en-têtes "X-Forwarded-*", le retrait des
+/// en-têtes "hop-by-hop" ou la réécriture du corps de la requête
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Inspecte ou modifie la requête juste avant sa transmission au serveur amont
+    async fn on_request(&self, req: &mut Request<Body>);
+
+    /// Inspecte ou modifie la réponse reçue du serveur amont avant qu'elle ne soit renvoyée au client
+    async fn on_response(&self, res: &mut Response<Body>);
+
+    /// Filtre optionnel permettant d'inspecter ou de transformer le corps de requête déjà mis en
+    /// mémoire tampon, avant la transmission vers le serveur amont. Ne modifie pas le corps par défaut
+    fn request_body_filter(&self, body: Bytes) -> Bytes {
+        body
+    }
+}
+
+/// Middleware intégré qui complète (ou crée) l'en-tête "X-Forwarded-For" avec l'adresse IP
+/// réelle du client, obtenue depuis la connexion plutôt que depuis un en-tête potentiellement
+/// falsifié par le client, et pose "X-Forwarded-Proto"
+pub struct ForwardedHeadersMiddleware;
+
+#[async_trait]
+impl Middleware for ForwardedHeadersMiddleware {
+    async fn on_request(&self, req: &mut Request<Body>) {
+        let client_ip = req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip().to_string());
+
+        if let Some(client_ip) = client_ip {
+            let forwarded_for = match req.headers().get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+                Some(existing) => format!("{}, {}", existing, client_ip),
+                None => client_ip,
+            };
+
+            if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+                req.headers_mut().insert("x-forwarded-for", value);
+            }
+        }
+
+        req.headers_mut().insert("x-forwarded-proto", HeaderValue::from_static("http"));
+    }
+
+    async fn on_response(&self, _res: &mut Response<Body>) {}
+}
+
+/// Middleware intégré qui retire les en-têtes "hop-by-hop" de la requête sortante et de la réponse,
+/// pour ne pas propager des en-têtes spécifiques à la connexion entrante
+pub struct HopByHopHeadersMiddleware;
+
+#[async_trait]
+impl Middleware for HopByHopHeadersMiddleware {
+    async fn on_request(&self, req: &mut Request<Body>) {
+        for header in HOP_BY_HOP_HEADERS {
+            req.headers_mut().remove(header);
+        }
+    }
+
+    async fn on_response(&self, res: &mut Response<Body>) {
+        for header in HOP_BY_HOP_HEADERS {
+            res.headers_mut().remove(header);
+        }
+    }
+}
@@ -3,45 +3,127 @@
 /// Redirige les requêtes vers les serveurs en fonction de leur disponibilité et de la politique de limitation de débit,
 /// assurant ainsi une répartition équilibrée du trafic et prévenant la surcharge des serveurs
 
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
 use hyper::{Body, Client, Request, Response, StatusCode};
+use rand::seq::SliceRandom;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{Mutex, RwLock};
+use tokio::time;
 use crate::upstream::UpstreamServer; // Représente un serveur
 use crate::rate_limiter::RateLimiter; // Gère la limitation du débit des requêtes
+use crate::load_balancer::LoadBalancingStrategy; // Stratégie de répartition de charge
+use crate::middleware::{ClientAddr, Middleware}; // Chaîne de middlewares requête/réponse
 use log::{info, warn};
 use once_cell::sync::Lazy; // Pour l'initialisation de l'index global
 
 /// Mutex protégeant un index global pour le round-robin
 static NEXT_SERVER_INDEX: Lazy<Mutex<usize>> = Lazy::new(|| Mutex::new(0));
 
-/// Traite une requête entrante et la redirige vers un serveur
+/// État mis en cache de l'expansion du round-robin pondéré
+///
+/// Reconstruire la liste étendue à chaque requête coûterait une allocation proportionnelle à la
+/// somme des poids configurés (potentiellement des milliers d'entrées) ; elle n'est donc
+/// reconstruite que lorsque l'ensemble sain ou les poids changent, repéré via "signature"
+struct WeightedExpansionCache {
+    /// Couples (URL, poids effectif) de l'ensemble sain ayant servi à construire l'expansion ;
+    /// comparée à chaque requête pour détecter un changement sans reconstruire la liste entière
+    signature: Vec<(String, u32)>,
+    /// URLs étendues selon le poids de chaque serveur, tournées en round-robin
+    expanded_urls: Vec<String>,
+    /// Prochain index à consommer dans "expanded_urls"
+    next_index: usize,
+}
+
+/// Mutex protégeant l'expansion pondérée mise en cache pour le round-robin pondéré
+static WEIGHTED_EXPANSION_CACHE: Lazy<Mutex<Option<WeightedExpansionCache>>> = Lazy::new(|| Mutex::new(None));
+
+/// Paramètres configurables du traitement d'une requête par le proxy
+#[derive(Clone, Copy, Debug)]
+pub struct ProxyConfig {
+    /// Taille maximale, en octets, du corps de requête mis en mémoire tampon pour permettre
+    /// de le rejouer contre plusieurs serveurs candidats en cas d'échec
+    pub max_buffered_body_size: u64,
+    /// Nombre maximal de serveurs candidats essayés avant d'abandonner la requête
+    pub max_retry_attempts: usize,
+    /// Délai maximal accordé à la lecture complète du corps de la requête entrante avant de la
+    /// mettre en mémoire tampon ; protège contre un client lent ou bloqué qui retiendrait
+    /// indéfiniment un emplacement de connexion
+    pub request_body_read_timeout: Duration,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            max_buffered_body_size: 10 * 1024 * 1024, // 10 Mo
+            max_retry_attempts: 3,
+            request_body_read_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Repère le nombre de connexions actives d'un serveur le temps d'une requête
+///
+/// Incrémente le compteur de connexions actives à la création et le décrémente
+/// automatiquement à la destruction. Pour une requête réussie, la destruction est reportée
+/// jusqu'à ce que le corps de la réponse soit entièrement relayé au client (voir
+/// "track_until_body_complete"), plutôt que de survenir dès la réception des en-têtes ; sinon
+/// "LeastConnections" cesserait de voir une connexion encore en cours pour tout serveur renvoyant
+/// un corps volumineux ou lent. Les retours anticipés en erreur restent couverts par la
+/// destruction de fin de portée habituelle
+struct ActiveConnectionGuard(Arc<AtomicUsize>);
+
+impl ActiveConnectionGuard {
+    fn new(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for ActiveConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Traite une requête entrante et la redirige vers un serveur, en retentant sur le prochain
+/// candidat sain lorsque le serveur choisi échoue
 ///
 /// # Arguments
 /// * "req" - La requête HTTP entrante
 /// * "rate_limiter" - Le gestionnaire de limitation de débit partagé
-/// * "servers" - La liste partagée des serveurs 
+/// * "servers" - La liste partagée des serveurs
 /// * "client" - Client HTTP pour effectuer les requêtes vers les serveurs
+/// * "strategy" - Stratégie de répartition de charge à utiliser pour ordonner les candidats
+/// * "middlewares" - Chaîne de middlewares exécutée autour de la transmission vers le serveur amont
+/// * "remote_addr" - Adresse du client distant telle qu'observée par la connexion entrante
+/// * "request_timeout" - Délai maximal accordé à une requête vers un serveur amont
+/// * "proxy_config" - Paramètres configurables du traitement de la requête (tailles, tentatives, délais)
 ///
 /// # Retour
-/// Renvoie une réponse HTTP résultant de la redirection vers un serveur ou un message d'erreur
-/// si aucune redirection n'est possible
+/// Renvoie une réponse HTTP résultant de la redirection vers un serveur, ou un message d'erreur
+/// si aucun serveur candidat n'a pu traiter la requête
 pub async fn proxy_request(
-    req: Request<Body>, 
-    rate_limiter: Arc<Mutex<RateLimiter>>,
-    servers: Arc<RwLock<Vec<UpstreamServer>>>, 
+    req: Request<Body>,
+    rate_limiter: Arc<RateLimiter>,
+    servers: Arc<RwLock<Vec<UpstreamServer>>>,
     client: Client<hyper::client::HttpConnector>,
+    strategy: LoadBalancingStrategy,
+    middlewares: Arc<Vec<Arc<dyn Middleware>>>,
+    remote_addr: SocketAddr,
+    request_timeout: Duration,
+    proxy_config: ProxyConfig,
 ) -> Result<Response<Body>, hyper::Error> {
-    // Extraction de l'adresse IP du client à partir de l'en-tête de la requête
-    let ip = req.headers()
-                .get("x-forwarded-for")
-                .and_then(|v| v.to_str().ok())
-                .unwrap_or_else(|| "unknown");
-    
+    // Clé de limitation de débit : l'adresse IP réelle du client telle qu'observée par la
+    // connexion entrante, et non l'en-tête "X-Forwarded-For" fourni par le client lui-même, qui
+    // peut être falsifié ou changé à chaque requête pour contourner la limite par IP
+    let ip = remote_addr.ip().to_string();
+
     // Vérifie si la requête dépasse la limite de débit autorisée
-    let is_allowed = {
-        let rate_limiter = rate_limiter.lock().await;
-        rate_limiter.check(ip) 
-    };
+    let is_allowed = rate_limiter.check(&ip).await;
 
     if !is_allowed {
         info!("Limitation du débit pour l'IP: {}", ip);
@@ -51,40 +133,204 @@ pub async fn proxy_request(
             .unwrap());
     }
 
-    // Sélectionne le serveur suivant en mode round-robin parmi les serveurs sains
-    let selected_server = select_next_server(servers).await;
+    let method = req.method().clone();
+    let path_and_query = req.uri().path_and_query().map(|x| x.as_str()).unwrap_or("/").to_string();
+    let mut headers = req.headers().clone();
 
-    // Envoie la requête au serveur sélectionné et renvoie la réponse obtenue
-    if let Some(server) = selected_server {
-        let uri_string = format!("{}{}", server.url, req.uri().path_and_query().map(|x| x.as_str()).unwrap_or("/"));
-        let new_req = Request::builder()
-            .method(req.method())
-            .uri(uri_string)
-            .body(req.into_body())
-            .expect("Failed to create the request");
+    // Met le corps de la requête en mémoire tampon pour pouvoir le rejouer contre plusieurs
+    // serveurs candidats : "into_body" ne peut être consommé qu'une seule fois. Bornée par un
+    // délai car un client lent ou bloqué pourrait sinon retenir indéfiniment un emplacement de
+    // connexion pendant que ce flux de lecture reste ouvert
+    let body_bytes = match time::timeout(
+        proxy_config.request_body_read_timeout,
+        buffer_request_body(req.into_body(), proxy_config.max_buffered_body_size),
+    ).await {
+        Ok(Ok(bytes)) => bytes,
+        Ok(Err(response)) => return Ok(response),
+        Err(_) => {
+            warn!("Délai dépassé lors de la lecture du corps de la requête entrante.");
+            return Ok(Response::builder()
+                .status(StatusCode::REQUEST_TIMEOUT)
+                .body(Body::from("Request Timeout"))
+                .unwrap());
+        }
+    };
 
-        info!("Transfert de la requête vers le serveur : {}", server.url);
-        let response = client.request(new_req).await?;
-        info!("Réponse reçue du serveur : {}", server.url);
-        info!("Statut de la réponse: {}", response.status());
-        Ok(response)
-    } else {
+    // Laisse chaque middleware inspecter ou transformer le corps mis en mémoire tampon avant
+    // qu'il ne soit transmis au serveur amont
+    let body_bytes = middlewares
+        .iter()
+        .fold(body_bytes, |body, middleware| middleware.request_body_filter(body));
+
+    // Retire le "Content-Length" d'origine : un middleware a pu redimensionner le corps, et
+    // conserver la longueur de la requête entrante enverrait un en-tête incohérent avec
+    // "body_bytes", risquant de tronquer ou de bloquer la lecture côté serveur amont
+    headers.remove(hyper::header::CONTENT_LENGTH);
+    headers.insert(hyper::header::CONTENT_LENGTH, hyper::header::HeaderValue::from(body_bytes.len() as u64));
+
+    // Ordonne les serveurs sains selon la stratégie de répartition de charge configurée,
+    // du candidat préféré au dernier recours
+    let candidates = select_candidates(servers, strategy).await;
+
+    if candidates.is_empty() {
         warn!("Aucun serveur sain disponible.");
-        Ok(Response::builder()
+        return Ok(Response::builder()
             .status(StatusCode::SERVICE_UNAVAILABLE)
             .body(Body::from("Service Unavailable"))
-            .unwrap())
+            .unwrap());
+    }
+
+    let attempts = candidates.len().min(proxy_config.max_retry_attempts);
+    for server in candidates.into_iter().take(attempts) {
+        // Suit la durée de vie de la connexion active pour la stratégie "LeastConnections" ;
+        // déplacé dans le corps de la réponse en cas de succès (voir plus bas), sinon décrémenté
+        // à la fin de cette itération
+        let connection_guard = ActiveConnectionGuard::new(server.active_connections.clone());
+
+        let uri_string = format!("{}{}", server.url, path_and_query);
+        let mut new_req = match Request::builder()
+            .method(method.clone())
+            .uri(uri_string)
+            .body(Body::from(body_bytes.clone()))
+        {
+            Ok(new_req) => new_req,
+            Err(e) => {
+                warn!("Impossible de construire la requête vers {} : {}", server.url, e);
+                continue;
+            }
+        };
+        *new_req.headers_mut() = headers.clone();
+        new_req.extensions_mut().insert(ClientAddr(remote_addr));
+
+        for middleware in middlewares.iter() {
+            middleware.on_request(&mut new_req).await;
+        }
+
+        info!("Transfert de la requête vers le serveur : {}", server.url);
+        match time::timeout(request_timeout, client.request(new_req)).await {
+            Ok(Ok(response)) if response.status().is_server_error() => {
+                warn!("{} a répondu avec une erreur serveur : {}", server.url, response.status());
+                mark_unhealthy(&server).await;
+            }
+            Ok(Ok(mut response)) => {
+                info!("Réponse reçue du serveur : {}", server.url);
+                info!("Statut de la réponse: {}", response.status());
+                for middleware in middlewares.iter().rev() {
+                    middleware.on_response(&mut response).await;
+                }
+                let (parts, body) = response.into_parts();
+                let body = track_until_body_complete(body, connection_guard);
+                return Ok(Response::from_parts(parts, body));
+            }
+            Ok(Err(e)) => {
+                warn!("Échec du transfert vers {} : {}", server.url, e);
+                mark_unhealthy(&server).await;
+            }
+            Err(_) => {
+                warn!("Délai dépassé lors du transfert vers {}.", server.url);
+                mark_unhealthy(&server).await;
+            }
+        }
+    }
+
+    warn!("Tous les serveurs candidats ont échoué.");
+    Ok(Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(Body::from("Service Unavailable"))
+        .unwrap())
+}
+
+/// Remplace le corps d'une réponse réussie par un corps en flux continu qui relaie chaque segment
+/// au client au fur et à mesure de sa réception depuis le serveur amont, et ne libère "guard"
+/// (donc ne décrémente le compteur de connexions actives du serveur) qu'une fois le relais
+/// terminé, que ce soit par la fin normale du corps, une erreur de lecture ou la fermeture
+/// anticipée de la connexion par le client
+///
+/// # Arguments
+/// * "body" - Le corps de la réponse reçue du serveur amont, encore à relayer au client
+/// * "guard" - Le compteur de connexions actives à maintenir tant que le relais n'est pas terminé
+fn track_until_body_complete(mut body: Body, guard: ActiveConnectionGuard) -> Body {
+    let (mut sender, tracked_body) = Body::channel();
+
+    tokio::spawn(async move {
+        // Conserve le garde jusqu'à la fin de cette tâche, une fois le corps entièrement relayé
+        let _guard = guard;
+
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        // Le client a fermé la connexion avant la fin du corps
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!("Erreur lors du relais du corps de la réponse : {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    tracked_body
+}
+
+/// Signale un échec de transfert, par exemple après une erreur 5xx ou un délai dépassé, au même
+/// compteur de sondes consécutives que la vérification de santé périodique, afin qu'un échec
+/// rencontré sur le chemin du proxy honore lui aussi le seuil configuré avant d'éjecter le serveur
+async fn mark_unhealthy(server: &UpstreamServer) {
+    server.record_probe_result(false).await;
+}
+
+/// Lit et met en mémoire tampon le corps d'une requête entrante, en rejetant les corps qui
+/// dépassent "max_size"
+///
+/// # Arguments
+/// * "body" - Le corps de la requête entrante à consommer
+/// * "max_size" - Taille maximale autorisée, en octets
+///
+/// # Retour
+/// Renvoie les octets du corps ou une réponse d'erreur prête à être renvoyée au client
+async fn buffer_request_body(mut body: Body, max_size: u64) -> Result<Bytes, Response<Body>> {
+    let mut buffer = BytesMut::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            warn!("Erreur lors de la lecture du corps de la requête : {}", e);
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Bad Request"))
+                .unwrap()
+        })?;
+
+        if buffer.len() as u64 + chunk.len() as u64 > max_size {
+            warn!("Corps de requête trop volumineux (> {} octets).", max_size);
+            return Err(Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Body::from("Payload Too Large"))
+                .unwrap());
+        }
+
+        buffer.extend_from_slice(&chunk);
     }
+
+    Ok(buffer.freeze())
 }
 
-/// Sélectionne le prochain serveur sain à utiliser pour la requête entrante en mode round-robin
+/// Construit la liste ordonnée des serveurs sains à essayer, du candidat préféré par la
+/// stratégie configurée jusqu'au dernier recours
 ///
 /// # Arguments
 /// * "servers" - La liste partagée des serveurs
+/// * "strategy" - Stratégie de répartition de charge à appliquer
 ///
 /// # Retour
-/// Renvoie une option contenant un serveur sain ou "None" si aucun serveur sain n'est disponible
-async fn select_next_server(servers: Arc<RwLock<Vec<UpstreamServer>>>) -> Option<UpstreamServer> {
+/// Renvoie les serveurs sains ordonnés ; une liste vide signifie qu'aucun serveur sain
+/// n'est disponible
+async fn select_candidates(
+    servers: Arc<RwLock<Vec<UpstreamServer>>>,
+    strategy: LoadBalancingStrategy,
+) -> Vec<UpstreamServer> {
     let servers_read = servers.read().await; // Accès sécurisé et asynchrone à la liste des serveurs
     let mut healthy_servers = Vec::new();
 
@@ -96,12 +342,127 @@ async fn select_next_server(servers: Arc<RwLock<Vec<UpstreamServer>>>) -> Option
     }
 
     if healthy_servers.is_empty() {
-        None
-    } else {
-        let mut next_index = NEXT_SERVER_INDEX.lock().await;
-        *next_index = *next_index % healthy_servers.len();
-        let server = healthy_servers[*next_index].clone();
-        *next_index = (*next_index + 1) % healthy_servers.len();
-        Some(server)
+        return Vec::new();
+    }
+
+    match strategy {
+        LoadBalancingStrategy::RoundRobin => order_round_robin(&healthy_servers).await,
+        LoadBalancingStrategy::Random => order_random(&healthy_servers),
+        LoadBalancingStrategy::WeightedRoundRobin => order_weighted_round_robin(&healthy_servers).await,
+        LoadBalancingStrategy::LeastConnections => order_least_connections(&healthy_servers),
+    }
+}
+
+/// Ordonne les serveurs sains en partant du prochain index round-robin, puis en complétant
+/// avec le reste des serveurs comme candidats de repli
+async fn order_round_robin(healthy_servers: &[&UpstreamServer]) -> Vec<UpstreamServer> {
+    let mut next_index = NEXT_SERVER_INDEX.lock().await;
+    *next_index = *next_index % healthy_servers.len();
+    let start = *next_index;
+    *next_index = (*next_index + 1) % healthy_servers.len();
+
+    (0..healthy_servers.len())
+        .map(|offset| healthy_servers[(start + offset) % healthy_servers.len()].clone())
+        .collect()
+}
+
+/// Ordonne les serveurs sains de façon aléatoire et uniforme
+fn order_random(healthy_servers: &[&UpstreamServer]) -> Vec<UpstreamServer> {
+    let mut shuffled: Vec<UpstreamServer> = healthy_servers.iter().map(|server| (*server).clone()).collect();
+    shuffled.shuffle(&mut rand::thread_rng());
+    shuffled
+}
+
+/// Ordonne les serveurs sains en répartissant le candidat préféré proportionnellement au poids
+/// de chaque serveur, en tournant en round-robin sur une expansion mise en cache ; le reste des
+/// serveurs sains sert de repli
+///
+/// L'expansion n'est reconstruite que lorsque l'ensemble sain ou les poids ont changé depuis le
+/// dernier appel, pour éviter de réallouer une liste proportionnelle à la somme des poids à
+/// chaque requête
+async fn order_weighted_round_robin(healthy_servers: &[&UpstreamServer]) -> Vec<UpstreamServer> {
+    // Un poids de 0 est traité comme 1 pour éviter d'exclure silencieusement un serveur
+    let signature: Vec<(String, u32)> = healthy_servers
+        .iter()
+        .map(|server| (server.url.clone(), server.weight.max(1)))
+        .collect();
+
+    let mut cache = WEIGHTED_EXPANSION_CACHE.lock().await;
+    let needs_rebuild = match &*cache {
+        Some(cached) => cached.signature != signature,
+        None => true,
+    };
+
+    if needs_rebuild {
+        let mut expanded_urls = Vec::new();
+        for (url, weight) in &signature {
+            for _ in 0..*weight {
+                expanded_urls.push(url.clone());
+            }
+        }
+        *cache = Some(WeightedExpansionCache { signature, expanded_urls, next_index: 0 });
+    }
+
+    let cache = cache.as_mut().unwrap();
+    cache.next_index %= cache.expanded_urls.len();
+    let primary_url = cache.expanded_urls[cache.next_index].clone();
+    cache.next_index = (cache.next_index + 1) % cache.expanded_urls.len();
+
+    let primary_position = healthy_servers.iter().position(|server| server.url == primary_url).unwrap_or(0);
+    (0..healthy_servers.len())
+        .map(|offset| healthy_servers[(primary_position + offset) % healthy_servers.len()].clone())
+        .collect()
+}
+
+/// Ordonne les serveurs sains du moins chargé au plus chargé en nombre de connexions actives
+fn order_least_connections(healthy_servers: &[&UpstreamServer]) -> Vec<UpstreamServer> {
+    let mut ordered: Vec<UpstreamServer> = healthy_servers.iter().map(|server| (*server).clone()).collect();
+    ordered.sort_by_key(|server| server.active_connections.load(Ordering::Relaxed));
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::HealthCheckConfig;
+    use std::collections::HashMap;
+
+    fn make_server(url: &str, weight: u32) -> UpstreamServer {
+        UpstreamServer::new(url.to_string(), weight, HealthCheckConfig::default())
+    }
+
+    // Un seul test exerce tout le cycle de vie du cache de l'expansion pondérée : "cargo test"
+    // exécute les tests en parallèle, or "WEIGHTED_EXPANSION_CACHE" est un état global partagé
+    // par toutes les invocations de "order_weighted_round_robin" ; les regrouper ici évite
+    // qu'un autre test n'écrase le cache pendant la séquence d'assertions
+    #[tokio::test]
+    async fn weighted_round_robin_distributes_by_weight_and_rebuilds_on_change() {
+        let a = make_server("http://weighted-test-a", 3);
+        let b = make_server("http://weighted-test-b", 1);
+        let healthy_servers = vec![&a, &b];
+
+        // Sur un cycle complet (somme des poids = 4), le candidat préféré doit suivre
+        // exactement la proportion des poids : 3 fois "a" pour 1 fois "b"
+        let mut primary_counts: HashMap<String, u32> = HashMap::new();
+        for _ in 0..4 {
+            let ordered = order_weighted_round_robin(&healthy_servers).await;
+            *primary_counts.entry(ordered[0].url.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(primary_counts.get("http://weighted-test-a"), Some(&3));
+        assert_eq!(primary_counts.get("http://weighted-test-b"), Some(&1));
+
+        // Change le poids de "a" : la signature ne correspond plus à l'expansion mise en cache,
+        // qui doit être reconstruite plutôt que de continuer à tourner sur l'ancienne répartition
+        let a_heavy = make_server("http://weighted-test-a", 1);
+        let b_heavy = make_server("http://weighted-test-b", 3);
+        let healthy_servers_after = vec![&a_heavy, &b_heavy];
+
+        let mut primary_counts_after: HashMap<String, u32> = HashMap::new();
+        for _ in 0..4 {
+            let ordered = order_weighted_round_robin(&healthy_servers_after).await;
+            *primary_counts_after.entry(ordered[0].url.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(primary_counts_after.get("http://weighted-test-a"), Some(&1));
+        assert_eq!(primary_counts_after.get("http://weighted-test-b"), Some(&3));
     }
 }
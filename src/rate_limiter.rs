@@ -1,42 +1,106 @@
-/// Gestionnaire de limitation de débit basé sur une fenêtre de temps
+/// Gestionnaire de limitation de débit, avec un backend en mémoire basé sur le "Generic Cell
+/// Rate Algorithm" (GCRA) ou un backend Redis pour une limitation partagée entre instances
 ///
 /// Ce module implémente un mécanisme de limitation de débit pour contrôler le nombre de requêtes
-/// qu'une adresse IP peut effectuer dans un intervalle de temps donné, afin de prévenir
-/// la surcharge du serveur
+/// qu'une adresse IP peut effectuer, afin de prévenir la surcharge du serveur. Le backend en
+/// mémoire lisse le débit dans le temps via le GCRA et évite qu'un client ne puisse doubler son
+/// quota autour d'une frontière de fenêtre. Le backend Redis permet à plusieurs instances du load
+/// balancer, placées derrière une même VIP, de partager le même compteur plutôt que d'appliquer
+/// chacune leur propre limite indépendante
 
+use bb8_redis::{bb8::Pool, RedisConnectionManager};
+use log::warn;
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 use std::collections::HashMap;
 
+/// Script Lua exécuté atomiquement sur Redis : incrémente le compteur de la fenêtre courante et
+/// pose son expiration uniquement lors de la toute première requête de la fenêtre, afin que le
+/// compteur retombe à zéro une fois la fenêtre écoulée
+const INCR_AND_EXPIRE_SCRIPT: &str = r#"
+local current = redis.call("INCR", KEYS[1])
+if tonumber(current) == 1 then
+    redis.call("EXPIRE", KEYS[1], ARGV[1])
+end
+return current
+"#;
+
+/// Backend de stockage des compteurs de limitation de débit
+enum RateLimiterBackend {
+    /// Backend en mémoire du processus, basé sur le GCRA
+    InMemory {
+        /// Temps d'arrivée théorique ("TAT") par IP, protégé par un Mutex pour une synchronisation
+        /// entre threads
+        arrivals: Mutex<HashMap<String, Instant>>,
+        /// Intervalle d'émission "T" : durée minimale entre deux requêtes au régime nominal,
+        /// soit "window / max_requests"
+        emission_interval: Duration,
+        /// Tolérance de rafale "τ" : durée maximale pendant laquelle des requêtes peuvent arriver
+        /// en avance sur le TAT, soit "T * (max_requests - 1)"
+        burst_tolerance: Duration,
+    },
+    /// Backend distribué s'appuyant sur un pool de connexions Redis partagé entre instances
+    Redis {
+        /// Pool de connexions Redis, établi une fois au démarrage
+        pool: Pool<RedisConnectionManager>,
+        /// Durée de la fenêtre de limitation, utilisée comme expiration de la clé Redis
+        window: Duration,
+        /// Nombre maximal de requêtes autorisées par adresse IP pendant la fenêtre de temps
+        max_requests: u32,
+    },
+}
+
 /// Structure représentant le gestionnaire de limitation de débit
 pub struct RateLimiter {
-    /// Compteur de requêtes par IP, protégé par un Mutex pour une synchronisation entre threads
-    requests: Mutex<HashMap<String, (u32, Instant)>>,
-    
-    /// Durée de la fenêtre de limitation de débit pendant laquelle le comptage des requêtes est effectué
-    window: Duration,
-    
-    /// Nombre maximal de requêtes autorisées par adresse IP pendant la fenêtre de temps
-    max_requests: u32,
+    backend: RateLimiterBackend,
 }
 
 impl RateLimiter {
-    /// Crée une nouvelle instance de "RateLimiter"
+    /// Crée une nouvelle instance de "RateLimiter" utilisant le backend en mémoire du processus
+    ///
+    /// # Arguments
+    ///
+    /// * "window_secs" - La durée de la fenêtre de limitation en secondes, utilisée pour dériver
+    ///   l'intervalle d'émission
+    /// * "max_requests" - Le nombre maximal de requêtes autorisées par fenêtre de temps par adresse IP
+    pub fn new_in_memory(window_secs: u64, max_requests: u32) -> Self {
+        let window = Duration::from_secs(window_secs);
+        let emission_interval = window / max_requests.max(1);
+        let burst_tolerance = emission_interval * max_requests.saturating_sub(1);
+
+        Self {
+            backend: RateLimiterBackend::InMemory {
+                arrivals: Mutex::new(HashMap::new()),
+                emission_interval,
+                burst_tolerance,
+            },
+        }
+    }
+
+    /// Crée une nouvelle instance de "RateLimiter" utilisant un backend Redis partagé, pour que
+    /// plusieurs instances du load balancer appliquent une limite cohérente à l'échelle du cluster
     ///
     /// # Arguments
     ///
+    /// * "pool" - Pool de connexions Redis "bb8"/"bb8-redis" établi au démarrage
     /// * "window_secs" - La durée de la fenêtre de limitation en secondes
     /// * "max_requests" - Le nombre maximal de requêtes autorisées par fenêtre de temps par adresse IP
-    pub fn new(window_secs: u64, max_requests: u32) -> Self {
+    pub fn new_redis(pool: Pool<RedisConnectionManager>, window_secs: u64, max_requests: u32) -> Self {
         Self {
-            requests: Mutex::new(HashMap::new()),
-            window: Duration::from_secs(window_secs),
-            max_requests,
+            backend: RateLimiterBackend::Redis {
+                pool,
+                window: Duration::from_secs(window_secs),
+                max_requests,
+            },
         }
     }
 
     /// Vérifie si une requête provenant d'une adresse IP spécifique est autorisée
-    /// en fonction de la politique de limitation de débit définie
+    ///
+    /// Avec le backend en mémoire, calcule le temps d'arrivée théorique ("TAT") de l'IP selon le
+    /// GCRA. Avec le backend Redis, incrémente atomiquement le compteur de la fenêtre courante via
+    /// un script Lua et compare le résultat à "max_requests". La méthode est asynchrone pour
+    /// accommoder l'aller-retour réseau du backend Redis
     ///
     /// # Arguments
     ///
@@ -45,20 +109,151 @@ impl RateLimiter {
     /// # Retour
     ///
     /// Renvoie "true" si la requête est autorisée, "false" sinon
-    pub fn check(&self, ip: &str) -> bool {
-        let mut requests = self.requests.lock().unwrap();
-        let current_time = Instant::now();
-
-        let entry = requests.entry(ip.to_string()).or_insert((0, current_time));
-
-        if current_time.duration_since(entry.1) > self.window {
-            *entry = (1, current_time);
-            true
-        } else if entry.0 < self.max_requests {
-                entry.0 += 1;
-                true
-            } else {
-                false
+    pub async fn check(&self, ip: &str) -> bool {
+        match &self.backend {
+            RateLimiterBackend::InMemory { arrivals, emission_interval, burst_tolerance } => {
+                Self::check_in_memory(arrivals, *emission_interval, *burst_tolerance, ip)
+            }
+            RateLimiterBackend::Redis { pool, window, max_requests } => {
+                Self::check_redis(pool, *window, *max_requests, ip).await
             }
+        }
+    }
+
+    /// Vérifie la limitation de débit selon le GCRA : si la requête arrive avant
+    /// "TAT - burst_tolerance", elle est rejetée ; sinon elle est acceptée et le TAT est avancé
+    /// de "emission_interval"
+    fn check_in_memory(
+        arrivals: &Mutex<HashMap<String, Instant>>,
+        emission_interval: Duration,
+        burst_tolerance: Duration,
+        ip: &str,
+    ) -> bool {
+        let mut arrivals = arrivals.lock().unwrap();
+        let now = Instant::now();
+
+        let tat = arrivals.get(ip).copied().unwrap_or(now);
+
+        // "now_allowed" peut être antérieur à toute "Instant" représentable si le TAT est encore
+        // proche de son origine ; dans ce cas la requête est simplement autorisée
+        if let Some(now_allowed) = tat.checked_sub(burst_tolerance) {
+            if now < now_allowed {
+                return false;
+            }
+        }
+
+        let new_tat = std::cmp::max(tat, now) + emission_interval;
+        arrivals.insert(ip.to_string(), new_tat);
+        true
     }
-} 
+
+    /// Vérifie la limitation de débit via Redis, en incrémentant atomiquement le compteur de la
+    /// fenêtre courante pour l'IP et en posant son expiration à la toute première requête
+    ///
+    /// En cas d'indisponibilité de Redis, la requête est autorisée par défaut plutôt que de
+    /// bloquer tout le trafic sur une dépendance externe en panne
+    async fn check_redis(pool: &Pool<RedisConnectionManager>, window: Duration, max_requests: u32, ip: &str) -> bool {
+        let mut conn = match pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Impossible d'obtenir une connexion Redis : {}, requête autorisée par défaut", e);
+                return true;
+            }
+        };
+
+        let key = format!("ratelimit:{}", ip);
+        let script = redis::Script::new(INCR_AND_EXPIRE_SCRIPT);
+        let count: i64 = match script
+            .key(key)
+            .arg(window.as_secs().max(1))
+            .invoke_async(&mut *conn)
+            .await
+        {
+            Ok(count) => count,
+            Err(e) => {
+                warn!("Échec du script Redis de limitation de débit : {}, requête autorisée par défaut", e);
+                return true;
+            }
+        };
+
+        count <= max_requests as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dérive "(emission_interval, burst_tolerance)" de la même façon que "new_in_memory",
+    /// pour exercer "check_in_memory" directement sans passer par le backend asynchrone
+    fn gcra_params(window_secs: u64, max_requests: u32) -> (Duration, Duration) {
+        let window = Duration::from_secs(window_secs);
+        let emission_interval = window / max_requests.max(1);
+        let burst_tolerance = emission_interval * max_requests.saturating_sub(1);
+        (emission_interval, burst_tolerance)
+    }
+
+    #[test]
+    fn allows_a_burst_up_to_the_configured_limit() {
+        let (emission_interval, burst_tolerance) = gcra_params(10, 5);
+        let arrivals = Mutex::new(HashMap::new());
+
+        for _ in 0..5 {
+            assert!(RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "1.2.3.4"));
+        }
+
+        // Le burst tolerance est épuisé : la requête suivante arrive trop tôt et est rejetée
+        assert!(!RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "1.2.3.4"));
+    }
+
+    #[test]
+    fn paces_requests_at_the_steady_state_rate_once_the_burst_is_consumed() {
+        let (emission_interval, burst_tolerance) = gcra_params(10, 5);
+        let arrivals = Mutex::new(HashMap::new());
+
+        for _ in 0..5 {
+            assert!(RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "1.2.3.4"));
+        }
+        assert!(!RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "1.2.3.4"));
+
+        // Après avoir attendu un intervalle d'émission complet, une seule requête supplémentaire
+        // est à nouveau autorisée : le débit en régime permanent reste borné à "max_requests"
+        std::thread::sleep(emission_interval);
+        assert!(RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "1.2.3.4"));
+        assert!(!RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "1.2.3.4"));
+    }
+
+    #[test]
+    fn does_not_let_an_idle_client_double_its_burst_by_waiting() {
+        let (emission_interval, burst_tolerance) = gcra_params(10, 5);
+        let arrivals = Mutex::new(HashMap::new());
+
+        // Une première requête enregistre un TAT pour le client
+        assert!(RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "1.2.3.4"));
+
+        // Laisse le client rester inactif bien au-delà de sa fenêtre de rafale
+        std::thread::sleep(burst_tolerance + emission_interval);
+
+        let mut allowed = 0;
+        for _ in 0..10 {
+            if RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "1.2.3.4") {
+                allowed += 1;
+            }
+        }
+
+        // Le TAT ne peut pas retomber derrière "now", donc l'inactivité ne permet pas d'accumuler
+        // plus que le burst d'origine ("max_requests") d'un coup
+        assert_eq!(allowed, 5);
+    }
+
+    #[test]
+    fn tracks_separate_ips_independently() {
+        let (emission_interval, burst_tolerance) = gcra_params(10, 1);
+        let arrivals = Mutex::new(HashMap::new());
+
+        assert!(RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "1.1.1.1"));
+        assert!(!RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "1.1.1.1"));
+        // Une autre IP dispose de son propre TAT et n'est pas pénalisée par la première
+        assert!(RateLimiter::check_in_memory(&arrivals, emission_interval, burst_tolerance, "2.2.2.2"));
+    }
+}
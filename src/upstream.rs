@@ -1,15 +1,46 @@
 /// Gère la vérification de l'état de santé des serveurs
-/// 
-/// Implémente une routine de vérification de santé utilisant des requêtes HTTP GET
-/// pour déterminer la disponibilité des serveurs. Une boucle asynchrone répète ces vérifications
-/// à intervalles réguliers
+///
+/// Implémente une routine de vérification de santé utilisant des requêtes HTTP GET vers un
+/// chemin de sonde dédié pour déterminer la disponibilité des serveurs. Un serveur ne bascule
+/// sain/non sain qu'après avoir franchi un seuil de sondes consécutives réussies ou échouées,
+/// ce qui évite qu'un simple aléa transitoire ne l'éjecte ou ne le réintègre instantanément.
+/// Une tâche asynchrone par serveur répète ces vérifications à l'intervalle qui lui est configuré
 
+use hyper::client::HttpConnector;
 use hyper::{Client, Uri, StatusCode};
 use log::{info, warn};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::time::{self, Duration};
 
+/// Configuration des vérifications de santé périodiques d'un serveur
+#[derive(Clone, Debug)]
+pub struct HealthCheckConfig {
+    /// Intervalle entre deux sondes consécutives
+    pub interval: Duration,
+    /// Délai maximal accordé à une sonde avant de la considérer en échec
+    pub request_timeout: Duration,
+    /// Chemin dédié interrogé par la sonde, plutôt que la racine du serveur
+    pub probe_path: String,
+    /// Nombre de sondes consécutives réussies nécessaires pour qu'un serveur non sain rejoigne la rotation
+    pub healthy_threshold: u32,
+    /// Nombre de sondes consécutives échouées nécessaires pour qu'un serveur sain soit éjecté
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(20),
+            request_timeout: Duration::from_secs(5),
+            probe_path: "/health".to_string(),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
 /// Représente un serveur avec son URL et son état de santé
 #[derive(Clone)]
 pub struct UpstreamServer {
@@ -17,6 +48,18 @@ pub struct UpstreamServer {
     pub url: String,
     /// Indicateur de l'état de santé du serveur, partagé et modifiable de manière asynchrone
     pub is_healthy: Arc<RwLock<bool>>,
+    /// Poids relatif du serveur, utilisé par la stratégie "WeightedRoundRobin"
+    /// pour répartir les requêtes proportionnellement
+    pub weight: u32,
+    /// Nombre de connexions actuellement en cours de traitement par ce serveur,
+    /// utilisé par la stratégie "LeastConnections"
+    pub active_connections: Arc<AtomicUsize>,
+    /// Configuration des vérifications de santé périodiques propre à ce serveur
+    pub health_check: HealthCheckConfig,
+    /// Nombre de sondes consécutives réussies depuis le dernier échec
+    consecutive_successes: Arc<AtomicU32>,
+    /// Nombre de sondes consécutives échouées depuis le dernier succès
+    consecutive_failures: Arc<AtomicU32>,
 }
 
 impl UpstreamServer {
@@ -25,55 +68,203 @@ impl UpstreamServer {
     /// # Arguments
     ///
     /// * "url" - URL du serveur
-    pub fn new(url: String) -> Self {
+    /// * "weight" - Poids relatif du serveur pour la répartition pondérée
+    /// * "health_check" - Configuration des vérifications de santé périodiques
+    pub fn new(url: String, weight: u32, health_check: HealthCheckConfig) -> Self {
         Self {
             url,
             is_healthy: Arc::new(RwLock::new(true)), // Initialise comme sain par défaut
+            weight,
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            health_check,
+            consecutive_successes: Arc::new(AtomicU32::new(0)),
+            consecutive_failures: Arc::new(AtomicU32::new(0)),
         }
     }
 
-    /// Vérifie l'état de santé du serveur en envoyant une requête HTTP GET
+    /// Vérifie l'état de santé du serveur en envoyant une requête HTTP GET sur son chemin de
+    /// sonde dédié, via le client HTTP partagé
     ///
-    /// Met à jour l'état de santé du serveur en fonction de la réponse à cette requête
-    pub async fn check_health(&self) {
-        let client = Client::new();
-        let uri = Uri::try_from(&*self.url).expect("Failed to parse URI");
+    /// Le résultat de la sonde alimente les compteurs de sondes consécutives réussies/échouées ;
+    /// "is_healthy" ne change d'état qu'après que le seuil configuré a été franchi
+    ///
+    /// # Arguments
+    /// * "client" - Client HTTP partagé, réutilisé entre les vérifications pour éviter l'ouverture
+    ///   d'une nouvelle connexion à chaque sonde
+    pub async fn check_health(&self, client: &Client<HttpConnector>) {
+        let probe_url = format!("{}{}", self.url, self.health_check.probe_path);
+        let uri = match Uri::try_from(probe_url.as_str()) {
+            Ok(uri) => uri,
+            Err(e) => {
+                warn!("URL de sonde invalide pour {} : {}", &self.url, e);
+                self.record_probe_result(false).await;
+                return;
+            }
+        };
+
+        let succeeded = match time::timeout(self.health_check.request_timeout, client.get(uri)).await {
+            Ok(Ok(response)) if response.status() == StatusCode::OK => true,
+            Ok(Ok(response)) => {
+                warn!("{} a répondu avec le statut: {}", &self.url, response.status());
+                false
+            }
+            Ok(Err(e)) => {
+                warn!("Échec de la vérification de santé pour {}: {}", &self.url, e);
+                false
+            }
+            Err(_) => {
+                warn!("Délai dépassé lors de la vérification de santé pour {}.", &self.url);
+                false
+            }
+        };
+
+        self.record_probe_result(succeeded).await;
+    }
 
-        match client.get(uri).await {
-            Ok(response) => {
+    /// Met à jour les compteurs de sondes consécutives et ne fait basculer "is_healthy" qu'une
+    /// fois le seuil configuré franchi
+    ///
+    /// Partagé entre la boucle de vérification de santé périodique et le chemin de transfert du
+    /// proxy, afin qu'un échec rencontré lors d'une requête réelle honore le même seuil de sondes
+    /// consécutives qu'un échec de sonde de santé
+    pub(crate) async fn record_probe_result(&self, succeeded: bool) {
+        if succeeded {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            let successes = self.consecutive_successes.fetch_add(1, Ordering::Relaxed) + 1;
+
+            if successes >= self.health_check.healthy_threshold {
                 let mut is_healthy = self.is_healthy.write().await;
-                *is_healthy = response.status() == StatusCode::OK;
-                if *is_healthy {
-                    info!("{} est UP.", &self.url);
-                } else {
-                    warn!("{} a répondu avec le statut: {}", &self.url, response.status());
+                if !*is_healthy {
+                    info!("{} est de nouveau UP après {} sondes réussies consécutives.", &self.url, successes);
                 }
-            },
-            Err(e) => {
+                *is_healthy = true;
+            }
+        } else {
+            self.consecutive_successes.store(0, Ordering::Relaxed);
+            let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+            if failures >= self.health_check.unhealthy_threshold {
                 let mut is_healthy = self.is_healthy.write().await;
+                if *is_healthy {
+                    warn!("{} est marqué DOWN après {} sondes échouées consécutives.", &self.url, failures);
+                }
                 *is_healthy = false;
-                warn!("Échec de la vérification de santé pour {}: {}", &self.url, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_server(healthy_threshold: u32, unhealthy_threshold: u32) -> UpstreamServer {
+        UpstreamServer::new(
+            "http://server-under-test".to_string(),
+            1,
+            HealthCheckConfig {
+                healthy_threshold,
+                unhealthy_threshold,
+                ..HealthCheckConfig::default()
             },
+        )
+    }
+
+    #[tokio::test]
+    async fn failures_below_the_unhealthy_threshold_do_not_eject_the_server() {
+        let server = make_server(2, 3);
+
+        server.record_probe_result(false).await;
+        server.record_probe_result(false).await;
+
+        assert!(*server.is_healthy.read().await, "2 échecs sur un seuil de 3 ne doivent pas éjecter le serveur");
+    }
+
+    #[tokio::test]
+    async fn reaching_the_unhealthy_threshold_ejects_the_server() {
+        let server = make_server(2, 3);
+
+        for _ in 0..3 {
+            server.record_probe_result(false).await;
+        }
+
+        assert!(!*server.is_healthy.read().await, "3 échecs consécutifs sur un seuil de 3 doivent éjecter le serveur");
+    }
+
+    #[tokio::test]
+    async fn a_success_resets_the_consecutive_failure_count() {
+        let server = make_server(2, 3);
+
+        server.record_probe_result(false).await;
+        server.record_probe_result(false).await;
+        server.record_probe_result(true).await; // Interrompt la séquence d'échecs consécutifs
+        server.record_probe_result(false).await;
+        server.record_probe_result(false).await;
+
+        assert!(*server.is_healthy.read().await, "les échecs non consécutifs ne doivent pas s'accumuler jusqu'au seuil");
+    }
+
+    #[tokio::test]
+    async fn an_unhealthy_server_needs_the_full_healthy_threshold_to_recover() {
+        let server = make_server(2, 3);
+
+        for _ in 0..3 {
+            server.record_probe_result(false).await;
+        }
+        assert!(!*server.is_healthy.read().await);
+
+        server.record_probe_result(true).await;
+        assert!(!*server.is_healthy.read().await, "une seule sonde réussie sur un seuil de 2 ne doit pas suffire à rétablir le serveur");
+
+        server.record_probe_result(true).await;
+        assert!(*server.is_healthy.read().await, "2 sondes réussies consécutives sur un seuil de 2 doivent rétablir le serveur");
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_success_streak_does_not_recover_the_server_early() {
+        let server = make_server(2, 3);
+
+        for _ in 0..3 {
+            server.record_probe_result(false).await;
         }
+        assert!(!*server.is_healthy.read().await);
+
+        server.record_probe_result(true).await;
+        server.record_probe_result(false).await; // Interrompt la séquence de succès consécutifs
+        server.record_probe_result(true).await;
+
+        assert!(!*server.is_healthy.read().await, "la séquence de succès interrompue ne doit pas atteindre le seuil de rétablissement");
     }
 }
 
-/// Exécute une boucle de vérification de santé pour tous les serveurs enregistrés,
-/// vérifiant leur état de santé à intervalles réguliers
+/// Lance une tâche de vérification de santé pour chaque serveur enregistré, chacune sondant à
+/// l'intervalle qui lui est propre
 ///
 /// # Arguments
 ///
 /// * "servers" - Liste partagée des serveurs à vérifier
+/// * "client" - Client HTTP partagé avec le chemin de transfert du proxy
 ///
-/// Cette routine lance une vérification immédiate au démarrage, puis continue à vérifier
-/// l'état de santé des serveurs toutes les 20 secondes
-pub async fn health_check_loop(servers: Arc<RwLock<Vec<UpstreamServer>>>) {
-    let mut interval = time::interval(Duration::from_secs(20));
-    loop {
-        interval.tick().await;
-        let servers_read = servers.read().await;
-        for server in servers_read.iter() {
-            server.check_health().await;
-        }
+/// Les serveurs ajoutés après le démarrage de cette routine ne sont pas pris en compte ;
+/// tous les serveurs doivent être enregistrés avant son lancement
+pub async fn health_check_loop(servers: Arc<RwLock<Vec<UpstreamServer>>>, client: Client<HttpConnector>) {
+    let servers_snapshot = servers.read().await.clone();
+
+    let tasks: Vec<_> = servers_snapshot
+        .into_iter()
+        .map(|server| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let mut interval = time::interval(server.health_check.interval);
+                loop {
+                    interval.tick().await;
+                    server.check_health(&client).await;
+                }
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        let _ = task.await;
     }
 }